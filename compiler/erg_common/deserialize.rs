@@ -1,7 +1,13 @@
 //! バイトコードからオブジェクトを復元する
+use std::fmt;
+use std::io::Read;
 use std::process;
 use std::string::FromUtf8Error;
 
+use num_bigint::{BigInt, BigUint, Sign};
+use num_traits::{ToPrimitive, Zero};
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+
 use crate::cache::Cache;
 use crate::codeobj::CodeObj;
 use crate::config::{ErgConfig, Input};
@@ -32,6 +38,20 @@ impl From<FromUtf8Error> for DeserializeError {
     }
 }
 
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.desc)
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+impl de::Error for DeserializeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::new(0, "serde::de::Error::custom", msg.to_string())
+    }
+}
+
 impl From<DeserializeError> for ErrorCore {
     fn from(err: DeserializeError) -> Self {
         ErrorCore::new(
@@ -80,15 +100,177 @@ impl DeserializeError {
             ),
         )
     }
+
+    /// バッファが尽きているのに、さらに読もうとした場合のエラー
+    pub fn unexpected_eof() -> Self {
+        Self::new(
+            0,
+            fn_name!(),
+            switch_lang!(
+                "japanese" => "ファイルの終端に達しましたが、まだ読み込むべきデータが残っています",
+                "english" => "reached the end of the file, but there is still data expected to be read",
+            ),
+        )
+    }
+
+    /// 要求したバイト数と、実際にバッファに残っているバイト数が一致しない場合のエラー
+    pub fn length_mismatch(expected: usize, found: usize) -> Self {
+        Self::new(
+            0,
+            fn_name!(),
+            switch_lang!(
+                "japanese" => format!("{expected}バイト読み込もうとしましたが、残り{found}バイトしかありません"),
+                "english" => format!("tried to read {expected} bytes, but only {found} bytes remain"),
+            ),
+        )
+    }
 }
 
 pub type DeserializeResult<T> = Result<T, DeserializeError>;
 
+/// marshalバイト列を読み進めるための抽象化。`Vec<u8>`を`drain`し続ける実装は
+/// 消費のたびにO(n)のシフトが発生するため、位置を進めるだけのカーソルに置き換える
+pub trait Reader {
+    fn read_u8(&mut self) -> DeserializeResult<u8>;
+    fn read_bytes(&mut self, len: usize) -> DeserializeResult<Vec<u8>>;
+    /// まだ読んでいない先頭のバイトを覗き見る。バッファが尽きていれば`None`を返す(エラーではない)
+    fn peek_u8(&mut self) -> Option<u8>;
+    fn is_empty(&mut self) -> bool;
+
+    fn read_array<const LEN: usize>(&mut self) -> DeserializeResult<[u8; LEN]> {
+        let bytes = self.read_bytes(LEN)?;
+        let mut arr = [0u8; LEN];
+        arr.copy_from_slice(&bytes);
+        Ok(arr)
+    }
+}
+
+/// メモリ上に読み込み済みの`.pyc`バイト列を、バッファを縮めずにインデックスを進めるだけで読む。
+/// ただし`read_bytes`は読んだ範囲を新しい`Vec<u8>`にコピーして返す(借用スライスは返さない)
+pub struct SliceReader<'r> {
+    buf: &'r [u8],
+    pos: usize,
+}
+
+impl<'r> SliceReader<'r> {
+    pub fn new(buf: &'r [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+}
+
+impl<'r> Reader for SliceReader<'r> {
+    fn read_u8(&mut self) -> DeserializeResult<u8> {
+        let b = *self
+            .buf
+            .get(self.pos)
+            .ok_or_else(DeserializeError::unexpected_eof)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> DeserializeResult<Vec<u8>> {
+        let remaining = self.buf.len() - self.pos;
+        if remaining < len {
+            return Err(DeserializeError::length_mismatch(len, remaining));
+        }
+        let bytes = self.buf[self.pos..self.pos + len].to_vec();
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn peek_u8(&mut self) -> Option<u8> {
+        self.buf.get(self.pos).copied()
+    }
+
+    fn is_empty(&mut self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
+/// `std::io::Read`を実装する任意のストリームから直接読む(ファイル全体をバッファしなくてよい)
+pub struct IoReader<R: Read> {
+    inner: R,
+    peeked: Option<u8>,
+}
+
+impl<R: Read> IoReader<R> {
+    /// `read_bytes`が一度に確保する最大サイズ。申告された長さをそのまま確保しないことで、
+    /// 長さを詐称する壊れた/悪意のあるストリームに対する無制限のアロケーションを避ける
+    const READ_CHUNK: usize = 64 * 1024;
+
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            peeked: None,
+        }
+    }
+}
+
+impl<R: Read> Reader for IoReader<R> {
+    fn read_u8(&mut self) -> DeserializeResult<u8> {
+        if let Some(b) = self.peeked.take() {
+            return Ok(b);
+        }
+        let mut byte = [0u8; 1];
+        self.inner
+            .read_exact(&mut byte)
+            .map_err(|_| DeserializeError::unexpected_eof())?;
+        Ok(byte[0])
+    }
+
+    fn read_bytes(&mut self, len: usize) -> DeserializeResult<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let mut bytes = Vec::with_capacity(len.min(Self::READ_CHUNK));
+        if let Some(b) = self.peeked.take() {
+            bytes.push(b);
+        }
+        // `len`はファイルが自己申告した値で信用できないため、一度に確保するのは
+        // READ_CHUNK分だけに留め、実際にストリームから読めた分だけ伸ばしていく
+        let mut chunk = [0u8; Self::READ_CHUNK];
+        while bytes.len() < len {
+            let want = (len - bytes.len()).min(Self::READ_CHUNK);
+            self.inner
+                .read_exact(&mut chunk[..want])
+                .map_err(|_| DeserializeError::unexpected_eof())?;
+            bytes.extend_from_slice(&chunk[..want]);
+        }
+        Ok(bytes)
+    }
+
+    fn peek_u8(&mut self) -> Option<u8> {
+        if self.peeked.is_none() {
+            let mut byte = [0u8; 1];
+            if self.inner.read_exact(&mut byte).is_ok() {
+                self.peeked = Some(byte[0]);
+            }
+        }
+        self.peeked
+    }
+
+    fn is_empty(&mut self) -> bool {
+        self.peek_u8().is_none()
+    }
+}
+
+/// marshal形式の型バイトの最上位ビット(0x80)は、そのオブジェクトが後から`TYPE_REF`で
+/// 参照されうることを示す(CPython 3.4+のFLAG_REF/reference-sharing機構)
+const FLAG_REF: u8 = 0x80;
+
+/// TYPE_LONGが主張しうるディジット数(15bit/ディジット)の上限。実在のCPython整数が
+/// ここまで巨大になることはまずないので、確保前にこれを超える値を壊れたファイルとして弾く
+const MAX_LONG_DIGITS: usize = 1_000_000;
+
 #[derive(Default)]
 pub struct Deserializer {
     str_cache: Cache<str>,
     arr_cache: Cache<[ValueObj]>,
     dict_cache: Cache<[(ValueObj, ValueObj)]>,
+    set_cache: Cache<[ValueObj]>,
+    /// FLAG_REFが立っていたオブジェクトを出現順に記録する参照テーブル
+    /// (`TYPE_REF`はこのテーブルへのインデックスとして現れる)
+    refs: Vec<ValueObj>,
 }
 
 impl Deserializer {
@@ -97,6 +279,8 @@ impl Deserializer {
             str_cache: Cache::new(),
             arr_cache: Cache::new(),
             dict_cache: Cache::new(),
+            set_cache: Cache::new(),
+            refs: Vec::new(),
         }
     }
 
@@ -107,9 +291,24 @@ impl Deserializer {
             eprintln!("{:?} is not a filename", cfg.input);
             process::exit(1);
         };
-        let codeobj = CodeObj::from_pyc(&filename[..])
-            .unwrap_or_else(|_| panic!("failed to deserialize {filename}"));
-        println!("{}", codeobj.code_info());
+        // 壊れた/悪意のある`.pyc`であってもコンパイラ自体を巻き込んでabortしないよう、
+        // エラーは通常のErrorCore経由で報告する
+        let bytes = match std::fs::read(&filename[..]) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                eprintln!("failed to read {filename}: {err}");
+                process::exit(1);
+            }
+        };
+        let mut reader = SliceReader::new(&bytes);
+        match Self::new().deserialize_pyc(&mut reader) {
+            Ok(codeobj) => println!("{}", codeobj.code_info()),
+            Err(err) => {
+                let core: ErrorCore = err.into();
+                eprintln!("failed to deserialize {filename}: {}", core.desc);
+                process::exit(1);
+            }
+        }
     }
 
     fn get_cached_str(&mut self, s: &str) -> ValueObj {
@@ -120,90 +319,193 @@ impl Deserializer {
         ValueObj::Array(self.arr_cache.get(arr))
     }
 
+    fn get_cached_set(&mut self, set: &[ValueObj]) -> ValueObj {
+        ValueObj::Set(self.set_cache.get(set))
+    }
+
     /// TODO: 使わない？
     pub fn get_cached_dict(&mut self, dict: &[(ValueObj, ValueObj)]) -> ValueObj {
         ValueObj::Dict(self.dict_cache.get(dict))
     }
 
-    pub fn vec_to_bytes<const LEN: usize>(vector: Vec<u8>) -> [u8; LEN] {
-        let mut arr = [0u8; LEN];
-        for (arr_elem, vec_elem) in arr.iter_mut().zip(vector.iter()) {
-            *arr_elem = *vec_elem;
-        }
-        arr
+    pub fn consume<const LEN: usize, R: Reader>(r: &mut R) -> DeserializeResult<[u8; LEN]> {
+        r.read_array::<LEN>()
     }
 
-    pub fn consume<const LEN: usize>(v: &mut Vec<u8>) -> [u8; LEN] {
-        Self::vec_to_bytes::<LEN>(v.drain(..LEN).collect::<Vec<_>>())
+    pub fn deserialize_u32<R: Reader>(r: &mut R) -> DeserializeResult<u32> {
+        Ok(u32::from_le_bytes(Self::consume::<4, R>(r)?))
     }
 
-    pub fn deserialize_u32(v: &mut Vec<u8>) -> u32 {
-        u32::from_le_bytes(Self::consume::<4>(v))
+    pub fn deserialize_u16<R: Reader>(r: &mut R) -> DeserializeResult<u16> {
+        Ok(u16::from_le_bytes(Self::consume::<2, R>(r)?))
+    }
+
+    pub fn deserialize_const<R: Reader>(
+        &mut self,
+        r: &mut R,
+        python_ver: u32,
+    ) -> DeserializeResult<ValueObj> {
+        let byte = r.read_u8()?;
+        let is_ref = byte & FLAG_REF != 0;
+        // 再帰的なコードオブジェクトが自身を参照できるよう、本体を読む前にスロットを確保しておく
+        let ref_idx = is_ref.then(|| {
+            let idx = self.refs.len();
+            self.refs.push(ValueObj::None);
+            idx
+        });
+        let obj = self.deserialize_const_body(r, python_ver, byte & !FLAG_REF)?;
+        if let Some(idx) = ref_idx {
+            self.refs[idx] = obj.clone();
+        }
+        Ok(obj)
     }
 
-    pub fn deserialize_const(
+    fn deserialize_const_body<R: Reader>(
         &mut self,
-        v: &mut Vec<u8>,
+        r: &mut R,
         python_ver: u32,
+        prefix_byte: u8,
     ) -> DeserializeResult<ValueObj> {
-        match DataTypePrefix::from(v.remove(0)) {
+        match DataTypePrefix::from(prefix_byte) {
+            DataTypePrefix::Ref => {
+                let idx = Self::deserialize_u32(r)? as usize;
+                self.refs.get(idx).cloned().ok_or_else(|| {
+                    DeserializeError::new(
+                        0,
+                        fn_name!(),
+                        switch_lang!(
+                            "japanese" => format!("不正な参照インデックスです: {idx}"),
+                            "english" => format!("invalid reference index: {idx}"),
+                        ),
+                    )
+                })
+            }
             DataTypePrefix::Int32 => {
-                let bytes = Self::consume::<4>(v);
+                let bytes = Self::consume::<4, R>(r)?;
                 Ok(ValueObj::Int(i32::from_le_bytes(bytes)))
             }
             DataTypePrefix::BinFloat => {
-                let bytes = Self::consume::<8>(v);
+                let bytes = Self::consume::<8, R>(r)?;
                 Ok(ValueObj::Float(f64::from_le_bytes(bytes)))
             }
             DataTypePrefix::ShortAscii | DataTypePrefix::ShortAsciiInterned => {
-                let len = v.remove(0);
-                let bytes = v.drain(..len as usize).collect();
+                let len = r.read_u8()?;
+                let bytes = r.read_bytes(len as usize)?;
                 Ok(self.get_cached_str(&String::from_utf8(bytes)?))
             }
             DataTypePrefix::Str | DataTypePrefix::Unicode => {
-                let len = Self::deserialize_u32(v);
-                let bytes = v.drain(..len as usize).collect();
+                let len = Self::deserialize_u32(r)?;
+                let bytes = r.read_bytes(len as usize)?;
                 Ok(self.get_cached_str(&String::from_utf8(bytes)?))
             }
+            // TYPE_STRING: 生のバイト列(UTF-8である保証がない)。Unicode/Strとは区別して保持する
+            DataTypePrefix::Bytes => {
+                let len = Self::deserialize_u32(r)?;
+                let bytes = r.read_bytes(len as usize)?;
+                Ok(ValueObj::Bytes(bytes))
+            }
+            // TYPE_LONG: 符号付き4バイトのディジット数nを読み、|n|個の15bitディジットから
+            // Σ digit[i]・2^(15・i) として多倍長整数を復元する(i32に収まらない値に対応するため)
+            DataTypePrefix::Long => {
+                let n = i32::from_le_bytes(Self::consume::<4, R>(r)?);
+                let digit_count = n.unsigned_abs() as usize;
+                // 壊れた/悪意のあるファイルがn = i32::MINのような値を詐称しても、
+                // 確保前に弾いて巨大なVec<u32>のアロケーションでabortしないようにする
+                if digit_count > MAX_LONG_DIGITS {
+                    return Err(DeserializeError::new(
+                        0,
+                        fn_name!(),
+                        switch_lang!(
+                            "japanese" => format!("TYPE_LONGのディジット数が大きすぎます: {digit_count}"),
+                            "english" => format!("TYPE_LONG digit count is too large: {digit_count}"),
+                        ),
+                    ));
+                }
+                let mut digits = Vec::with_capacity(digit_count);
+                for _ in 0..digit_count {
+                    digits.push(Self::deserialize_u16(r)? as u32);
+                }
+                let mut magnitude = BigInt::from(0u32);
+                for digit in digits.into_iter().rev() {
+                    magnitude = (magnitude << 15) + BigInt::from(digit);
+                }
+                let value = if n < 0 { -magnitude } else { magnitude };
+                Ok(ValueObj::BigInt(value))
+            }
+            DataTypePrefix::Complex | DataTypePrefix::BinaryComplex => {
+                let re = f64::from_le_bytes(Self::consume::<8, R>(r)?);
+                let im = f64::from_le_bytes(Self::consume::<8, R>(r)?);
+                Ok(ValueObj::Complex(re, im))
+            }
+            // TYPE_DICT: キー/バリューの組を、終端を示すNULL(0)の型バイトが現れるまで読み続ける
+            DataTypePrefix::Dict => {
+                let mut pairs = Vec::new();
+                loop {
+                    match r.peek_u8() {
+                        Some(b) if b & !FLAG_REF == DataTypePrefix::Null as u8 => {
+                            r.read_u8()?;
+                            break;
+                        }
+                        None => return Err(DeserializeError::unexpected_eof()),
+                        _ => {
+                            let key = self.deserialize_const(r, python_ver)?;
+                            let value = self.deserialize_const(r, python_ver)?;
+                            pairs.push((key, value));
+                        }
+                    }
+                }
+                Ok(self.get_cached_dict(&pairs))
+            }
+            // `len`はファイルの自己申告値で信用できないため、Dictと同様に確保済み容量を
+            // 信用せず、実際に読めた要素の分だけ伸ばしていく(各要素の読み込みは残りバイト数に
+            // よって自然に制限されるので、巨大なlenを詐称されても先読みのアロケーションは起きない)
+            DataTypePrefix::Set | DataTypePrefix::FrozenSet => {
+                let len = Self::deserialize_u32(r)?;
+                let mut elems = Vec::new();
+                for _ in 0..len {
+                    elems.push(self.deserialize_const(r, python_ver)?);
+                }
+                Ok(self.get_cached_set(&elems))
+            }
             DataTypePrefix::True => Ok(ValueObj::True),
             DataTypePrefix::False => Ok(ValueObj::False),
             DataTypePrefix::SmallTuple => {
-                let len = v.remove(0);
-                let mut arr = Vec::with_capacity(len as usize);
+                let len = r.read_u8()?;
+                let mut arr = Vec::new();
                 for _ in 0..len {
-                    arr.push(self.deserialize_const(v, python_ver)?);
+                    arr.push(self.deserialize_const(r, python_ver)?);
                 }
                 Ok(self.get_cached_arr(&arr))
             }
             DataTypePrefix::Tuple => {
-                let len = Self::deserialize_u32(v);
-                let mut arr = Vec::with_capacity(len as usize);
+                let len = Self::deserialize_u32(r)?;
+                let mut arr = Vec::new();
                 for _ in 0..len {
-                    arr.push(self.deserialize_const(v, python_ver)?);
+                    arr.push(self.deserialize_const(r, python_ver)?);
                 }
                 Ok(self.get_cached_arr(&arr))
             }
             DataTypePrefix::Code => {
-                let argcount = Self::deserialize_u32(v);
+                let argcount = Self::deserialize_u32(r)?;
                 let posonlyargcount = if python_ver >= 3413 {
-                    Self::deserialize_u32(v)
+                    Self::deserialize_u32(r)?
                 } else {
                     0
                 };
-                let kwonlyargcount = Self::deserialize_u32(v);
-                let nlocals = Self::deserialize_u32(v);
-                let stacksize = Self::deserialize_u32(v);
-                let flags = Self::deserialize_u32(v);
-                let code = self.deserialize_bytes(v)?;
-                let consts = self.deserialize_const_vec(v, python_ver)?;
-                let names = self.deserialize_str_vec(v, python_ver)?;
-                let varnames = self.deserialize_str_vec(v, python_ver)?;
-                let freevars = self.deserialize_str_vec(v, python_ver)?;
-                let cellvars = self.deserialize_str_vec(v, python_ver)?;
-                let filename = self.deserialize_str(v, python_ver)?;
-                let name = self.deserialize_str(v, python_ver)?;
-                let firstlineno = Self::deserialize_u32(v);
-                let lnotab = self.deserialize_bytes(v)?;
+                let kwonlyargcount = Self::deserialize_u32(r)?;
+                let nlocals = Self::deserialize_u32(r)?;
+                let stacksize = Self::deserialize_u32(r)?;
+                let flags = Self::deserialize_u32(r)?;
+                let code = self.deserialize_bytes(r, python_ver)?;
+                let consts = self.deserialize_const_vec(r, python_ver)?;
+                let names = self.deserialize_str_vec(r, python_ver)?;
+                let varnames = self.deserialize_str_vec(r, python_ver)?;
+                let freevars = self.deserialize_str_vec(r, python_ver)?;
+                let cellvars = self.deserialize_str_vec(r, python_ver)?;
+                let filename = self.deserialize_str(r, python_ver)?;
+                let name = self.deserialize_str(r, python_ver)?;
+                let firstlineno = Self::deserialize_u32(r)?;
+                let lnotab = self.deserialize_bytes(r, python_ver)?;
                 Ok(ValueObj::from(CodeObj::new(
                     argcount,
                     posonlyargcount,
@@ -235,23 +537,23 @@ impl Deserializer {
         }
     }
 
-    pub fn deserialize_const_vec(
+    pub fn deserialize_const_vec<R: Reader>(
         &mut self,
-        v: &mut Vec<u8>,
+        r: &mut R,
         python_ver: u32,
     ) -> DeserializeResult<Vec<ValueObj>> {
-        match self.deserialize_const(v, python_ver)? {
+        match self.deserialize_const(r, python_ver)? {
             ValueObj::Array(arr) => Ok(arr.to_vec()),
             other => Err(DeserializeError::type_error(&Type::Str, other.ref_t())),
         }
     }
 
-    pub fn deserialize_const_array(
+    pub fn deserialize_const_array<R: Reader>(
         &mut self,
-        v: &mut Vec<u8>,
+        r: &mut R,
         python_ver: u32,
     ) -> DeserializeResult<RcArray<ValueObj>> {
-        match self.deserialize_const(v, python_ver)? {
+        match self.deserialize_const(r, python_ver)? {
             ValueObj::Array(arr) => Ok(arr),
             other => Err(DeserializeError::type_error(&Type::Str, other.ref_t())),
         }
@@ -268,12 +570,12 @@ impl Deserializer {
         }
     }
 
-    pub fn deserialize_str_vec(
+    pub fn deserialize_str_vec<R: Reader>(
         &mut self,
-        v: &mut Vec<u8>,
+        r: &mut R,
         python_ver: u32,
     ) -> DeserializeResult<Vec<Str>> {
-        match self.deserialize_const(v, python_ver)? {
+        match self.deserialize_const(r, python_ver)? {
             ValueObj::Array(arr) => {
                 let mut strs = Vec::with_capacity(arr.len());
                 for c in arr.iter().cloned() {
@@ -288,25 +590,518 @@ impl Deserializer {
         }
     }
 
-    pub fn deserialize_str(&mut self, v: &mut Vec<u8>, python_ver: u32) -> DeserializeResult<Str> {
-        match self.deserialize_const(v, python_ver)? {
+    pub fn deserialize_str<R: Reader>(
+        &mut self,
+        r: &mut R,
+        python_ver: u32,
+    ) -> DeserializeResult<Str> {
+        match self.deserialize_const(r, python_ver)? {
             ValueObj::Str(s) => Ok(s),
             other => Err(DeserializeError::type_error(&Type::Str, other.ref_t())),
         }
     }
 
-    pub fn deserialize_bytes(&self, v: &mut Vec<u8>) -> DeserializeResult<Vec<u8>> {
-        if DataTypePrefix::from(v.remove(0)) != DataTypePrefix::Str {
+    /// `code`/`lnotab`はTYPE_STRING(空のbytesシングルトンは高頻度でFLAG_REFが立つ)として
+    /// 現れるため、`deserialize_const`を通して読み、refテーブルの同期も合わせて行う
+    pub fn deserialize_bytes<R: Reader>(
+        &mut self,
+        r: &mut R,
+        python_ver: u32,
+    ) -> DeserializeResult<Vec<u8>> {
+        match self.deserialize_const(r, python_ver)? {
+            ValueObj::Bytes(bytes) => Ok(bytes),
+            other => Err(DeserializeError::type_error(&Type::Str, other.ref_t())),
+        }
+    }
+
+    /// marshalのバイト列から直接`#[derive(Deserialize)]`された型を読み出す。
+    /// 一度`deserialize_const`でオブジェクトグラフを復元し、その上を`ValueObjDeserializer`で
+    /// 辿ることでserdeのエコシステム(テストフィクスチャや`.pyc`インスペクタなど)に接続する
+    pub fn deserialize_as<'de, T, R>(&mut self, r: &mut R, python_ver: u32) -> DeserializeResult<T>
+    where
+        T: de::Deserialize<'de>,
+        R: Reader,
+    {
+        let value = self.deserialize_const(r, python_ver)?;
+        T::deserialize(ValueObjDeserializer { value })
+    }
+}
+
+/// 既に復元済みの`ValueObj`を、serdeの`Deserializer`として読むためのアダプタ。
+/// タプル/リスト/集合を`visit_seq`に、辞書を`visit_map`に、整数(i32)・浮動小数点数・真偽値・
+/// 文字列・バイト列・`None`をそれぞれの`visit_*`にマッピングする。
+/// 多倍長整数・複素数・コードオブジェクトはエラーになる
+pub struct ValueObjDeserializer {
+    value: ValueObj,
+}
+
+impl ValueObjDeserializer {
+    pub fn new(value: ValueObj) -> Self {
+        Self { value }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueObjDeserializer {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> DeserializeResult<V::Value> {
+        match self.value {
+            ValueObj::Int(i) => visitor.visit_i32(i),
+            ValueObj::Float(f) => visitor.visit_f64(f),
+            ValueObj::True => visitor.visit_bool(true),
+            ValueObj::False => visitor.visit_bool(false),
+            ValueObj::None => visitor.visit_unit(),
+            ValueObj::Str(s) => visitor.visit_string(s.to_string()),
+            ValueObj::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+            ValueObj::Array(arr) => visitor.visit_seq(ValueObjSeqAccess {
+                iter: arr.to_vec().into_iter(),
+            }),
+            ValueObj::Set(set) => visitor.visit_seq(ValueObjSeqAccess {
+                iter: set.to_vec().into_iter(),
+            }),
+            ValueObj::Dict(dict) => visitor.visit_map(ValueObjMapAccess {
+                iter: dict.to_vec().into_iter(),
+                value: None,
+            }),
+            other => Err(DeserializeError::new(
+                0,
+                fn_name!(),
+                switch_lang!(
+                    "japanese" => format!("このオブジェクトはserdeで復元できません: {}", other),
+                    "english" => format!("cannot deserialize this object via serde: {}", other),
+                ),
+            )),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> DeserializeResult<V::Value> {
+        match self.value {
+            ValueObj::None => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct ValueObjSeqAccess {
+    iter: std::vec::IntoIter<ValueObj>,
+}
+
+impl<'de> SeqAccess<'de> for ValueObjSeqAccess {
+    type Error = DeserializeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> DeserializeResult<Option<T::Value>> {
+        match self.iter.next() {
+            Some(value) => seed.deserialize(ValueObjDeserializer { value }).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+struct ValueObjMapAccess {
+    iter: std::vec::IntoIter<(ValueObj, ValueObj)>,
+    value: Option<ValueObj>,
+}
+
+impl<'de> MapAccess<'de> for ValueObjMapAccess {
+    type Error = DeserializeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> DeserializeResult<Option<K::Value>> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(ValueObjDeserializer { value: key }).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> DeserializeResult<V::Value> {
+        let value = self
+            .value
+            .take()
+            .expect("next_value_seed called before next_key_seed");
+        seed.deserialize(ValueObjDeserializer { value })
+    }
+}
+
+/// `ValueObj`/`CodeObj`をmarshal形式のバイト列に書き出す、`Deserializer`の逆変換にあたる型。
+/// `deserialize_const`が読むのと同じ順序(タグ→リトルエンディアンの長さ→本体)で書く
+#[derive(Default)]
+pub struct Serializer {
+    buf: Vec<u8>,
+}
+
+impl Serializer {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn push_u8(&mut self, b: u8) {
+        self.buf.push(b);
+    }
+
+    fn push_u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn serialize_bytes(&mut self, bytes: &[u8]) {
+        self.push_u8(DataTypePrefix::Bytes as u8);
+        self.push_u32(bytes.len() as u32);
+        self.push_bytes(bytes);
+    }
+
+    pub fn serialize_str(&mut self, s: &str) {
+        self.push_u8(DataTypePrefix::Unicode as u8);
+        self.push_u32(s.len() as u32);
+        self.push_bytes(s.as_bytes());
+    }
+
+    pub fn serialize_str_vec(&mut self, strs: &[Str]) {
+        self.push_u8(DataTypePrefix::Tuple as u8);
+        self.push_u32(strs.len() as u32);
+        for s in strs {
+            self.serialize_str(s);
+        }
+    }
+
+    pub fn serialize_const_vec(&mut self, consts: &[ValueObj], python_ver: u32) {
+        self.push_u8(DataTypePrefix::Tuple as u8);
+        self.push_u32(consts.len() as u32);
+        for c in consts {
+            self.serialize_const(c, python_ver);
+        }
+    }
+
+    pub fn serialize_const(&mut self, value: &ValueObj, python_ver: u32) {
+        match value {
+            ValueObj::Int(i) => {
+                self.push_u8(DataTypePrefix::Int32 as u8);
+                self.push_bytes(&i.to_le_bytes());
+            }
+            ValueObj::Float(f) => {
+                self.push_u8(DataTypePrefix::BinFloat as u8);
+                self.push_bytes(&f.to_le_bytes());
+            }
+            // TYPE_LONG: |n|個の15bitディジットに分解し、最下位ディジットから順に書く
+            // (deserialize_const_bodyのDataTypePrefix::Longアームと対になる)
+            ValueObj::BigInt(big) => {
+                self.push_u8(DataTypePrefix::Long as u8);
+                let base = BigUint::from(0x8000u32);
+                let mut magnitude = big.magnitude().clone();
+                let mut digits: Vec<u16> = Vec::new();
+                while !magnitude.is_zero() {
+                    digits.push((&magnitude % &base).to_u16().unwrap_or(0));
+                    magnitude /= &base;
+                }
+                let n = match big.sign() {
+                    Sign::Minus => -(digits.len() as i32),
+                    _ => digits.len() as i32,
+                };
+                self.push_bytes(&n.to_le_bytes());
+                for digit in digits {
+                    self.push_bytes(&digit.to_le_bytes());
+                }
+            }
+            ValueObj::Complex(re, im) => {
+                self.push_u8(DataTypePrefix::BinaryComplex as u8);
+                self.push_bytes(&re.to_le_bytes());
+                self.push_bytes(&im.to_le_bytes());
+            }
+            ValueObj::Str(s) => self.serialize_str(s),
+            ValueObj::Bytes(bytes) => {
+                self.push_u8(DataTypePrefix::Bytes as u8);
+                self.push_u32(bytes.len() as u32);
+                self.push_bytes(bytes);
+            }
+            ValueObj::True => self.push_u8(DataTypePrefix::True as u8),
+            ValueObj::False => self.push_u8(DataTypePrefix::False as u8),
+            ValueObj::None => self.push_u8(DataTypePrefix::None as u8),
+            ValueObj::Array(arr) => {
+                self.push_u8(DataTypePrefix::Tuple as u8);
+                self.push_u32(arr.len() as u32);
+                for elem in arr.iter() {
+                    self.serialize_const(elem, python_ver);
+                }
+            }
+            ValueObj::Set(set) => {
+                self.push_u8(DataTypePrefix::Set as u8);
+                self.push_u32(set.len() as u32);
+                for elem in set.iter() {
+                    self.serialize_const(elem, python_ver);
+                }
+            }
+            // TYPE_DICT: キー/バリューを交互に書き、終端をNULLバイトで示す
+            ValueObj::Dict(dict) => {
+                self.push_u8(DataTypePrefix::Dict as u8);
+                for (key, value) in dict.iter() {
+                    self.serialize_const(key, python_ver);
+                    self.serialize_const(value, python_ver);
+                }
+                self.push_u8(DataTypePrefix::Null as u8);
+            }
+            ValueObj::Code(code) => self.serialize_code(code, python_ver),
+        }
+    }
+
+    /// `deserialize_const_body`の`DataTypePrefix::Code`アームが読むのと同じフィールド順で書く
+    pub fn serialize_code(&mut self, code: &CodeObj, python_ver: u32) {
+        self.push_u8(DataTypePrefix::Code as u8);
+        self.push_u32(code.argcount());
+        if python_ver >= 3413 {
+            self.push_u32(code.posonlyargcount());
+        }
+        self.push_u32(code.kwonlyargcount());
+        self.push_u32(code.nlocals());
+        self.push_u32(code.stacksize());
+        self.push_u32(code.flags());
+        self.serialize_bytes(code.code());
+        self.serialize_const_vec(code.consts(), python_ver);
+        self.serialize_str_vec(code.names());
+        self.serialize_str_vec(code.varnames());
+        self.serialize_str_vec(code.freevars());
+        self.serialize_str_vec(code.cellvars());
+        self.serialize_str(code.filename());
+        self.serialize_str(code.name());
+        self.push_u32(code.firstlineno());
+        self.serialize_bytes(code.lnotab());
+    }
+
+    /// PEP 552形式の`.pyc`ヘッダ: マジックナンバー(2バイト)+ `\r\n` + ビットフィールドフラグ +
+    /// タイムスタンプベースの無効化情報(mtime + ソースサイズ)。ハッシュベースの無効化は未対応
+    pub fn write_pyc_header(&mut self, python_ver: u32, source_mtime: u32, source_size: u32) {
+        self.push_bytes(&(python_ver as u16).to_le_bytes());
+        self.push_bytes(&[0x0d, 0x0a]);
+        self.push_u32(0); // bit 0 = 0: タイムスタンプベースの無効化
+        self.push_u32(source_mtime);
+        self.push_u32(source_size);
+    }
+
+    /// `CodeObj`から、実行可能な`.pyc`ファイルと同じ内容のバイト列を組み立てる
+    pub fn to_pyc_bytes(code: &CodeObj, python_ver: u32, source_mtime: u32, source_size: u32) -> Vec<u8> {
+        let mut ser = Self::new();
+        ser.write_pyc_header(python_ver, source_mtime, source_size);
+        ser.serialize_code(code, python_ver);
+        ser.into_bytes()
+    }
+}
+
+/// このデシリアライザが認識できるmarshalリビジョン(=`.pyc`のマジックナンバー)の一覧。
+/// `python_ver`の値はCPythonの`Lib/importlib/_bootstrap_external.py`のMAGIC_NUMBERと一致する。
+/// 網羅的な表はCPython本体が持つのでそちらを正とし、ここでは代表的なバージョンのみ載せる
+const KNOWN_MAGIC_NUMBERS: &[u32] = &[
+    3413, // Python 3.8
+    3425, // Python 3.9
+    3439, // Python 3.10
+    3495, // Python 3.11
+];
+
+/// PEP 552で定義された`.pyc`ヘッダ。マジックナンバーから`python_ver`を自己検証的に導出する
+pub struct PycHeader {
+    pub python_ver: u32,
+    pub is_hash_based: bool,
+    pub check_source_hash: bool,
+    pub mtime: Option<u32>,
+    pub source_size: Option<u32>,
+    pub source_hash: Option<u64>,
+}
+
+impl PycHeader {
+    pub fn read<R: Reader>(r: &mut R) -> DeserializeResult<Self> {
+        let magic = u16::from_le_bytes(Deserializer::consume::<2, R>(r)?) as u32;
+        let crlf = r.read_bytes(2)?;
+        if crlf != [0x0d, 0x0a] || !KNOWN_MAGIC_NUMBERS.contains(&magic) {
             return Err(DeserializeError::new(
                 0,
                 fn_name!(),
                 switch_lang!(
-                    "japanese" => "バイト列の読み込みに失敗しました",
-                    "english" => "failed to load bytes",
+                    "japanese" => format!("未知または不正な.pycマジックナンバーです: {magic}"),
+                    "english" => format!("unknown or invalid .pyc magic number: {magic}"),
                 ),
             ));
         }
-        let len = Self::deserialize_u32(v);
-        Ok(v.drain(0..len as usize).collect())
+        let flags = Deserializer::deserialize_u32(r)?;
+        let is_hash_based = flags & 0b01 != 0;
+        let check_source_hash = flags & 0b10 != 0;
+        let (mtime, source_size, source_hash) = if is_hash_based {
+            let hash = u64::from_le_bytes(Deserializer::consume::<8, R>(r)?);
+            (None, None, Some(hash))
+        } else {
+            let mtime = Deserializer::deserialize_u32(r)?;
+            let source_size = Deserializer::deserialize_u32(r)?;
+            (Some(mtime), Some(source_size), None)
+        };
+        Ok(Self {
+            python_ver: magic,
+            is_hash_based,
+            check_source_hash,
+            mtime,
+            source_size,
+            source_hash,
+        })
+    }
+}
+
+impl Deserializer {
+    /// `.pyc`のヘッダを読んでバージョンを自己検証した上で、本体のコードオブジェクトを復元する。
+    /// `python_ver`を呼び出し側から渡す必要がなくなり、ファイルが自称するバージョンと
+    /// 実際のフォーマット分岐(`posonlyargcount`など)が常に一致することが保証される
+    pub fn deserialize_pyc<R: Reader>(&mut self, r: &mut R) -> DeserializeResult<CodeObj> {
+        let header = PycHeader::read(r)?;
+        match self.deserialize_const(r, header.python_ver)? {
+            ValueObj::Code(code) => Ok(code),
+            other => Err(DeserializeError::new(
+                0,
+                fn_name!(),
+                switch_lang!(
+                    "japanese" => format!(".pycのトップレベルはコードオブジェクトであるべきですが、{}でした", other.ref_t()),
+                    "english" => format!("the top-level object of a .pyc should be a code object, but got {}", other.ref_t()),
+                ),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn const_round_trips_through_serializer_and_deserializer() {
+        let consts = vec![
+            ValueObj::Int(-42),
+            ValueObj::Str(Str::from("hello")),
+            ValueObj::True,
+            ValueObj::None,
+        ];
+        for value in consts {
+            let mut ser = Serializer::new();
+            ser.serialize_const(&value, 3413);
+            let bytes = ser.into_bytes();
+            let mut reader = SliceReader::new(&bytes);
+            let restored = Deserializer::new()
+                .deserialize_const(&mut reader, 3413)
+                .unwrap();
+            assert_eq!(value, restored);
+        }
+    }
+
+    #[test]
+    fn truncated_dict_is_an_error_not_a_panic() {
+        let mut ser = Serializer::new();
+        ser.serialize_const(&ValueObj::Int(1), 3413);
+        ser.serialize_const(&ValueObj::Int(2), 3413);
+        // DataTypePrefix::Dict but no NULL terminator, simulating a cut-off file
+        let mut bytes = vec![DataTypePrefix::Dict as u8];
+        bytes.extend(ser.into_bytes());
+        let mut reader = SliceReader::new(&bytes);
+        let result = Deserializer::new().deserialize_const(&mut reader, 3413);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn long_dict_set_bytes_complex_round_trip() {
+        let mut deser = Deserializer::new();
+        let dict = deser.get_cached_dict(&[(ValueObj::Int(1), ValueObj::Int(2))]);
+        let set = deser.get_cached_set(&[ValueObj::Int(3), ValueObj::Int(4)]);
+        let consts = vec![
+            ValueObj::BigInt(BigInt::from(i64::from(i32::MAX)) + BigInt::from(1)),
+            ValueObj::BigInt(-BigInt::from(i64::from(i32::MAX)) - BigInt::from(2)),
+            ValueObj::Bytes(vec![0, 1, 2, 255]),
+            ValueObj::Complex(1.5, -2.5),
+            dict,
+            set,
+        ];
+        for value in consts {
+            let mut ser = Serializer::new();
+            ser.serialize_const(&value, 3413);
+            let bytes = ser.into_bytes();
+            let mut reader = SliceReader::new(&bytes);
+            let restored = deser.deserialize_const(&mut reader, 3413).unwrap();
+            assert_eq!(value, restored);
+        }
+    }
+
+    #[test]
+    fn type_ref_resolves_to_the_earlier_flag_ref_object() {
+        // SmallTuple(len=2) of [ShortAscii("hi") with FLAG_REF set, Ref(0)]
+        let mut bytes = vec![DataTypePrefix::SmallTuple as u8, 2];
+        bytes.push(DataTypePrefix::ShortAscii as u8 | FLAG_REF);
+        bytes.push(2); // ShortAscii length
+        bytes.extend_from_slice(b"hi");
+        bytes.push(DataTypePrefix::Ref as u8);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut reader = SliceReader::new(&bytes);
+        let mut deser = Deserializer::new();
+        let restored = deser.deserialize_const(&mut reader, 3413).unwrap();
+        let expected = deser.array_into_const(&[
+            ValueObj::Str(Str::from("hi")),
+            ValueObj::Str(Str::from("hi")),
+        ]);
+        assert_eq!(restored, expected);
+        assert_eq!(deser.refs.len(), 1);
+    }
+
+    #[test]
+    fn pyc_header_parses_timestamp_based_header() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&3413u16.to_le_bytes());
+        bytes.extend_from_slice(&[0x0d, 0x0a]);
+        bytes.extend_from_slice(&0u32.to_le_bytes()); // flags: timestamp-based
+        bytes.extend_from_slice(&12345u32.to_le_bytes()); // mtime
+        bytes.extend_from_slice(&6789u32.to_le_bytes()); // source_size
+        let mut reader = SliceReader::new(&bytes);
+        let header = PycHeader::read(&mut reader).unwrap();
+        assert_eq!(header.python_ver, 3413);
+        assert!(!header.is_hash_based);
+        assert_eq!(header.mtime, Some(12345));
+        assert_eq!(header.source_size, Some(6789));
+        assert_eq!(header.source_hash, None);
+    }
+
+    #[test]
+    fn pyc_header_parses_hash_based_header() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&3413u16.to_le_bytes());
+        bytes.extend_from_slice(&[0x0d, 0x0a]);
+        bytes.extend_from_slice(&0b01u32.to_le_bytes()); // flags: hash-based
+        bytes.extend_from_slice(&0xdead_beef_0000_0001u64.to_le_bytes());
+        let mut reader = SliceReader::new(&bytes);
+        let header = PycHeader::read(&mut reader).unwrap();
+        assert!(header.is_hash_based);
+        assert_eq!(header.mtime, None);
+        assert_eq!(header.source_hash, Some(0xdead_beef_0000_0001));
+    }
+
+    #[test]
+    fn pyc_header_rejects_unknown_magic() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&0xffffu16.to_le_bytes());
+        bytes.extend_from_slice(&[0x0d, 0x0a]);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        let mut reader = SliceReader::new(&bytes);
+        assert!(PycHeader::read(&mut reader).is_err());
     }
 }